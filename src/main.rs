@@ -1,54 +1,298 @@
 use anyhow::{anyhow, Result};
-use clap::Parser;
+use chrono::NaiveDateTime;
+use clap::{Parser, Subcommand};
+use git2::{Repository, Signature};
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use rusqlite::Connection;
 use serde::Deserialize;
 use simple_logger::SimpleLogger;
+use xxhash_rust::xxh3::Xxh3;
 
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+/// How often the pending-path poller re-checks configured paths that were
+/// not resolvable when they were registered.
+const PENDING_POLL_INTERVAL_SECS: u64 = 5;
+
+/// Commented default configuration written out on a zero-argument first run.
+const DEFAULT_CONFIG: &[u8] = include_bytes!("default_config.yaml");
+
+/// How a rule stores its snapshots.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// Write a timestamped copy of the file into the backup directory.
+    #[default]
+    Copy,
+    /// Commit the changed file into a git repository at the backup directory.
+    Git,
+}
+
+/// Optional per-rule limits on how many snapshots are retained. Any
+/// combination may be set; a snapshot is pruned if it exceeds any limit.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+struct Retention {
+    /// Keep at most this many of the most recent snapshots.
+    keep_last: Option<usize>,
+    /// Delete snapshots older than this many days.
+    max_age_days: Option<u64>,
+    /// Delete oldest snapshots until their combined size is within this budget.
+    /// The newest snapshot is always kept, even if it alone exceeds the budget.
+    max_total_bytes: Option<u64>,
+}
+
+impl Retention {
+    fn is_set(&self) -> bool {
+        self.keep_last.is_some() || self.max_age_days.is_some() || self.max_total_bytes.is_some()
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct BackupRule {
     file_path: String,
     backup_dir_path: String,
+    #[serde(default)]
+    backend: Backend,
+    #[serde(flatten)]
+    retention: Retention,
+}
+
+/// The resolved backup destination for a watched source file.
+#[derive(Debug, Clone)]
+struct BackupTarget {
+    backup_dir: PathBuf,
+    backend: Backend,
+    retention: Retention,
 }
 
 #[derive(Debug, Deserialize)]
 struct ConfigFile {
     rules: Vec<BackupRule>,
+    /// Where to keep the SQLite snapshot index. Defaults to `snapper.db`
+    /// next to the configuration file.
+    #[serde(default)]
+    index_db_path: Option<String>,
+}
+
+/// The parsed configuration: the source-to-backup-dir lookup and the
+/// resolved location of the snapshot index database.
+struct ParsedConfig {
+    /// Rules whose source file resolved at parse time, keyed by canonical path.
+    output_dir_lookup: HashMap<PathBuf, BackupTarget>,
+    /// Rules whose source file did not resolve yet, keyed by the configured
+    /// (non-canonical) path so the poller can retry resolving it later.
+    pending: HashMap<PathBuf, BackupTarget>,
+    index_db_path: PathBuf,
+}
+
+fn parse_config_file(config_file: &File, config_path: &Path) -> Result<ParsedConfig> {
+    let config: ConfigFile = serde_yaml::from_reader(config_file)?;
+
+    let mut output_dir_lookup = HashMap::new();
+    let mut pending = HashMap::new();
+
+    for rule in config.rules {
+        let target = BackupTarget {
+            backup_dir: Path::new(&rule.backup_dir_path).canonicalize().map_err(|e| {
+                anyhow!("Failed to canonicalize directory {:?}: {e}", rule.backup_dir_path)
+            })?,
+            backend: rule.backend,
+            retention: rule.retention,
+        };
+
+        match Path::new(&rule.file_path).canonicalize() {
+            Ok(canonical) if canonical.is_file() => {
+                output_dir_lookup.insert(canonical, target);
+            }
+            _ => {
+                log::warn!(
+                    "{:?} does not resolve to a file yet; watching for it to appear.",
+                    rule.file_path
+                );
+                pending.insert(PathBuf::from(&rule.file_path), target);
+            }
+        }
+    }
+
+    let index_db_path = match config.index_db_path {
+        Some(path) => PathBuf::from(path),
+        None => config_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join("snapper.db"),
+    };
+
+    Ok(ParsedConfig {
+        output_dir_lookup,
+        pending,
+        index_db_path,
+    })
+}
+
+/// The standard locations searched for a configuration file when none is
+/// given on the command line, in priority order. The first entry is also the
+/// location a default config is written to on a first run.
+fn config_search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(config_dir) = dirs::config_dir() {
+        paths.push(config_dir.join("snapper").join("snapper.yaml"));
+    }
+    if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".snapper.yaml"));
+    }
+    paths
 }
 
-fn parse_config_file(config_file: &File) -> HashMap<PathBuf, PathBuf> {
-    let config: ConfigFile =
-        serde_yaml::from_reader(config_file).expect("Failed to parse config file.");
+/// Resolve the configuration file to use. Returns the explicit path when one
+/// was given; otherwise searches the standard locations and, if none exist,
+/// writes the embedded default config to the preferred location and returns
+/// that.
+fn resolve_config_path(explicit: Option<String>) -> PathBuf {
+    if let Some(path) = explicit {
+        return PathBuf::from(path);
+    }
+
+    let candidates = config_search_paths();
+    if let Some(existing) = candidates.iter().find(|path| path.is_file()) {
+        log::debug!("Using discovered configuration at {existing:?}.");
+        return existing.clone();
+    }
 
-    config
-        .rules
+    let preferred = candidates
         .into_iter()
-        .map(|rule| {
-            (
-                Path::new(&rule.file_path)
-                    .canonicalize()
-                    .expect("Failed to canonicalize file path."),
-                Path::new(&rule.backup_dir_path)
-                    .canonicalize()
-                    .expect("Failed to canonicalize directory path."),
-            )
-        })
-        .collect::<HashMap<_, _>>()
+        .next()
+        .expect("Couldn't determine a location to write the default configuration to.");
+
+    log::info!("No configuration found; writing a default one to {preferred:?}.");
+    if let Some(parent) = preferred.parent() {
+        std::fs::create_dir_all(parent)
+            .unwrap_or_else(|e| panic!("Failed to create config directory {parent:?}. {e:?}"));
+    }
+    std::fs::write(&preferred, DEFAULT_CONFIG)
+        .unwrap_or_else(|e| panic!("Failed to write default configuration. {e:?}"));
+
+    preferred
+}
+
+/// Open (creating if needed) the SQLite snapshot index, ensuring the
+/// `snapshots` table exists.
+fn open_index(db_path: &Path) -> Result<Connection> {
+    let connection = Connection::open(db_path)?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS snapshots (
+            id INTEGER PRIMARY KEY,
+            source_path TEXT NOT NULL,
+            backup_path TEXT NOT NULL,
+            timestamp TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            content_hash TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(connection)
+}
+
+/// Resolve `file` to an absolute, canonicalized path without requiring the
+/// file itself to still exist — the common restore case is recovering a file
+/// that was deleted. The parent directory (which normally still exists) is
+/// canonicalized and the file name re-joined so the result matches the
+/// `source_path` recorded at backup time.
+fn absolutize(file: &str) -> Result<PathBuf> {
+    let path = Path::new(file);
+    if let Ok(canonical) = path.canonicalize() {
+        return Ok(canonical);
+    }
+
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    let parent = absolute
+        .parent()
+        .ok_or_else(|| anyhow!("Can't determine the parent directory of {file:?}."))?;
+    let file_name = absolute
+        .file_name()
+        .ok_or_else(|| anyhow!("{file:?} does not name a file."))?;
+
+    Ok(parent.canonicalize()?.join(file_name))
+}
+
+/// Find the backup path for the newest snapshot of `source_key` (or the newest
+/// at or before `at`) recorded in the index.
+fn select_snapshot(index: &Connection, source_key: &str, at: Option<&str>) -> Result<String> {
+    let result = match at {
+        Some(at) => index.query_row(
+            "SELECT backup_path FROM snapshots
+             WHERE source_path = ?1 AND timestamp <= ?2
+             ORDER BY timestamp DESC LIMIT 1",
+            rusqlite::params![source_key, at],
+            |row| row.get(0),
+        ),
+        None => index.query_row(
+            "SELECT backup_path FROM snapshots
+             WHERE source_path = ?1
+             ORDER BY timestamp DESC LIMIT 1",
+            rusqlite::params![source_key],
+            |row| row.get(0),
+        ),
+    };
+
+    result.map_err(|e| match at {
+        Some(at) => anyhow!("No snapshot of {source_key:?} at or before {at:?}: {e}"),
+        None => anyhow!("No snapshot recorded for {source_key:?}: {e}"),
+    })
+}
+
+/// Restore the newest snapshot of `file` (or the newest at or before `at`)
+/// recorded in the index, copying it back over the original path. Git-backed
+/// rules don't populate the index, so point the user at git recovery instead.
+fn restore_snapshot(
+    index: &Connection,
+    output_dir_lookup: &HashMap<PathBuf, BackupTarget>,
+    file: &str,
+    at: Option<&str>,
+) -> Result<()> {
+    let source_path = absolutize(file)?;
+    let source_key = source_path
+        .to_str()
+        .ok_or_else(|| anyhow!("Source path is not valid UTF-8: {source_path:?}"))?;
+
+    if let Some(target) = output_dir_lookup.get(&source_path) {
+        if target.backend == Backend::Git {
+            return Err(anyhow!(
+                "{file:?} is backed up with the git backend, which isn't indexed for \
+                 `restore`. Recover it with `git -C {:?} log`/`git checkout` instead.",
+                target.backup_dir
+            ));
+        }
+    }
+
+    let backup_path = select_snapshot(index, source_key, at)?;
+
+    log::info!("Restoring {source_path:?} from {backup_path:?}.");
+    std::fs::copy(&backup_path, &source_path)?;
+
+    Ok(())
 }
 
 fn start_file_watcher(
-    parsed_config: &HashMap<PathBuf, PathBuf>,
+    parsed_config: &HashMap<PathBuf, BackupTarget>,
 ) -> (RecommendedWatcher, Receiver<DebouncedEvent>) {
     let (tx, rx) = mpsc::channel();
     let mut watcher =
         notify::watcher(tx, Duration::from_secs(5)).expect("Failed to create file change watcher.");
 
-    for (file_path, backup_dir_path) in parsed_config {
+    for (file_path, target) in parsed_config {
+        let backup_dir_path = &target.backup_dir;
         if !file_path.is_file() {
             log::error!("Can't monitor {file_path:?}. Does not exist or is not a file.");
             continue;
@@ -59,7 +303,7 @@ fn start_file_watcher(
             continue;
         }
 
-        if let Err(e) = std::fs::create_dir_all(&backup_dir_path) {
+        if let Err(e) = std::fs::create_dir_all(backup_dir_path) {
             log::error!("Failed to create backup location {backup_dir_path:?}. {e:?}");
         }
 
@@ -72,45 +316,408 @@ fn start_file_watcher(
     (watcher, rx)
 }
 
-fn process_write_event(
-    changed_path: &Path,
-    output_dir_lookup: &HashMap<PathBuf, PathBuf>,
-) -> Result<()> {
-    let canonical_path = changed_path.canonicalize()?;
+/// Compute the `xxh3_128` hash of a file's contents, streaming the bytes
+/// through the hasher so we never hold the whole file in memory.
+fn hash_file(path: &Path) -> Result<u128> {
+    let mut file = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buf = [0u8; 64 * 1024];
 
-    let backup_dir = output_dir_lookup
-        .get(&canonical_path)
-        .ok_or_else(|| anyhow!("Don't have a backup rule for file at {canonical_path:?}."))?;
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
 
-    let curr_time = chrono::Utc::now();
+    Ok(hasher.digest128())
+}
 
+/// Write a timestamped copy of the source file into the backup directory and
+/// record the snapshot in the index.
+fn backup_by_copy(
+    canonical_path: &Path,
+    backup_dir: &Path,
+    timestamp: &str,
+    content_hash: u128,
+    index: &Connection,
+    retention: &Retention,
+) -> Result<()> {
+    let source_basename = canonical_path.file_name().ok_or_else(|| {
+        anyhow!("The path the write event happened at is not a file: {canonical_path:?}")
+    })?;
     let backup_file_name = format!(
         "{}-{}",
-        curr_time.format("%Y%m%d-%H%M%S-%6f"),
-        canonical_path
-            .file_name()
-            .ok_or_else(|| anyhow!(
-                "The path the write event happened at is not a file: {canonical_path:?}"
-            ))?
+        timestamp,
+        source_basename
             .to_str()
             .ok_or_else(|| anyhow!("Failed to format final backup file name."))?
     );
 
     log::debug!("Backing up {canonical_path:?} to {backup_dir:?}/{backup_file_name}.");
-    std::fs::copy(
-        canonical_path,
-        backup_dir.join(Path::new(&backup_file_name)),
+    let backup_path = backup_dir.join(Path::new(&backup_file_name));
+    std::fs::copy(canonical_path, &backup_path)?;
+
+    let size = std::fs::metadata(&backup_path)?.len();
+    index.execute(
+        "INSERT INTO snapshots (source_path, backup_path, timestamp, size, content_hash)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![
+            canonical_path.to_string_lossy(),
+            backup_path.to_string_lossy(),
+            timestamp,
+            size,
+            format!("{content_hash:032x}"),
+        ],
     )?;
 
+    if retention.is_set() {
+        if let Err(e) = prune_backups(backup_dir, source_basename, retention, index) {
+            log::error!("Failed to prune old backups in {backup_dir:?}: {e:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Enumerate this rule's existing snapshots in `backup_dir` (matched by the
+/// source file's basename) and delete the ones exceeding the configured
+/// retention limits, oldest first, removing their index rows too so `restore`
+/// never selects a pruned snapshot.
+fn prune_backups(
+    backup_dir: &Path,
+    source_basename: &OsStr,
+    retention: &Retention,
+    index: &Connection,
+) -> Result<()> {
+    let suffix = format!("-{}", source_basename.to_string_lossy());
+
+    // (timestamp, path, size), one per snapshot belonging to this rule.
+    let mut snapshots: Vec<(NaiveDateTime, PathBuf, u64)> = Vec::new();
+    for entry in std::fs::read_dir(backup_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if let Some(timestamp) = file_name.strip_suffix(&suffix) {
+            if let Ok(parsed) = NaiveDateTime::parse_from_str(timestamp, "%Y%m%d-%H%M%S-%6f") {
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                snapshots.push((parsed, entry.path(), size));
+            }
+        }
+    }
+
+    // Oldest first, so pruning walks from the oldest snapshot.
+    snapshots.sort_by_key(|(timestamp, _, _)| *timestamp);
+
+    let mut doomed: std::collections::HashSet<PathBuf> = std::collections::HashSet::new();
+
+    if let Some(keep_last) = retention.keep_last {
+        if snapshots.len() > keep_last {
+            for (_, path, _) in &snapshots[..snapshots.len() - keep_last] {
+                doomed.insert(path.clone());
+            }
+        }
+    }
+
+    if let Some(max_age_days) = retention.max_age_days {
+        let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(max_age_days as i64);
+        for (timestamp, path, _) in &snapshots {
+            if *timestamp < cutoff {
+                doomed.insert(path.clone());
+            }
+        }
+    }
+
+    if let Some(max_total_bytes) = retention.max_total_bytes {
+        let mut surviving_bytes: u64 = snapshots
+            .iter()
+            .filter(|(_, path, _)| !doomed.contains(path))
+            .map(|(_, _, size)| size)
+            .sum();
+        // Never delete the most recent snapshot (the one just written), even
+        // if it alone exceeds the budget — walk all but the newest entry.
+        let prunable = snapshots.len().saturating_sub(1);
+        for (_, path, size) in &snapshots[..prunable] {
+            if surviving_bytes <= max_total_bytes {
+                break;
+            }
+            if doomed.insert(path.clone()) {
+                surviving_bytes -= size;
+            }
+        }
+    }
+
+    for (_, path, _) in &snapshots {
+        if doomed.contains(path) {
+            match std::fs::remove_file(path) {
+                Ok(()) => log::info!("Pruned old backup {path:?} per retention policy."),
+                Err(e) => log::error!("Failed to prune backup {path:?}: {e:?}"),
+            }
+            if let Err(e) = index.execute(
+                "DELETE FROM snapshots WHERE backup_path = ?1",
+                rusqlite::params![path.to_string_lossy()],
+            ) {
+                log::error!("Failed to remove index row for pruned backup {path:?}: {e:?}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Commit the current contents of the source file into a git repository
+/// rooted at the backup directory, under a stable per-file path.
+fn backup_by_git(canonical_path: &Path, backup_dir: &Path, timestamp: &str) -> Result<()> {
+    let repo = Repository::open(backup_dir).or_else(|_| Repository::init(backup_dir))?;
+
+    let file_name = canonical_path
+        .file_name()
+        .ok_or_else(|| anyhow!("The write event path is not a file: {canonical_path:?}"))?;
+    std::fs::copy(canonical_path, backup_dir.join(file_name))?;
+
+    let relative = Path::new(file_name);
+    let mut git_index = repo.index()?;
+    git_index.add_path(relative)?;
+    git_index.write()?;
+    let tree = repo.find_tree(git_index.write_tree()?)?;
+
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now("snapper", "snapper@localhost"))?;
+    let message = format!("{timestamp} {}", canonical_path.display());
+
+    let parent = repo
+        .head()
+        .ok()
+        .and_then(|head| head.target())
+        .and_then(|oid| repo.find_commit(oid).ok());
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+
+    log::debug!("Committing {canonical_path:?} into git repo at {backup_dir:?}.");
+    repo.commit(Some("HEAD"), &signature, &signature, &message, &tree, &parents)?;
+
+    Ok(())
+}
+
+fn process_write_event(
+    changed_path: &Path,
+    output_dir_lookup: &HashMap<PathBuf, BackupTarget>,
+    last_hashes: &mut HashMap<PathBuf, u128>,
+    index: &Connection,
+) -> Result<()> {
+    let canonical_path = changed_path.canonicalize()?;
+
+    let target = output_dir_lookup
+        .get(&canonical_path)
+        .ok_or_else(|| anyhow!("Don't have a backup rule for file at {canonical_path:?}."))?;
+
+    let new_hash = hash_file(&canonical_path)?;
+    if last_hashes.get(&canonical_path) == Some(&new_hash) {
+        log::debug!("Contents of {canonical_path:?} unchanged, skipping redundant backup.");
+        return Ok(());
+    }
+
+    let curr_time = chrono::Utc::now();
+    let timestamp = curr_time.format("%Y%m%d-%H%M%S-%6f").to_string();
+
+    match target.backend {
+        Backend::Copy => backup_by_copy(
+            &canonical_path,
+            &target.backup_dir,
+            &timestamp,
+            new_hash,
+            index,
+            &target.retention,
+        )?,
+        Backend::Git => backup_by_git(&canonical_path, &target.backup_dir, &timestamp)?,
+    }
+
+    last_hashes.insert(canonical_path, new_hash);
+
     Ok(())
 }
 
+/// Shared watcher state threaded between the main event loop and the
+/// pending-path poller so both can arm and disarm watches.
+#[derive(Clone)]
+struct WatchState {
+    watcher: Arc<Mutex<RecommendedWatcher>>,
+    output_dir_lookup: Arc<Mutex<HashMap<PathBuf, BackupTarget>>>,
+    pending: Arc<Mutex<HashMap<PathBuf, BackupTarget>>>,
+    last_hashes: Arc<Mutex<HashMap<PathBuf, u128>>>,
+    index: Arc<Mutex<Connection>>,
+}
+
+/// Spawn a background thread that periodically retries the pending paths,
+/// arming a watch and synthesizing an initial backup once one resolves.
+fn spawn_pending_poller(state: WatchState) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(PENDING_POLL_INTERVAL_SECS));
+
+        let resolved: Vec<(PathBuf, PathBuf, BackupTarget)> = {
+            let pending = state.pending.lock().unwrap();
+            pending
+                .iter()
+                .filter_map(|(configured, target)| {
+                    configured
+                        .canonicalize()
+                        .ok()
+                        .filter(|canonical| canonical.is_file())
+                        .map(|canonical| (configured.clone(), canonical, target.clone()))
+                })
+                .collect()
+        };
+
+        for (configured, canonical, target) in resolved {
+            log::info!("Pending path {configured:?} resolved to {canonical:?}; arming watch.");
+
+            if let Err(e) = state
+                .watcher
+                .lock()
+                .unwrap()
+                .watch(&canonical, RecursiveMode::NonRecursive)
+            {
+                log::error!("Failed to watch newly resolved {canonical:?}: {e:?}");
+                continue;
+            }
+
+            state
+                .output_dir_lookup
+                .lock()
+                .unwrap()
+                .insert(canonical.clone(), target);
+            state.pending.lock().unwrap().remove(&configured);
+
+            let lookup = state.output_dir_lookup.lock().unwrap();
+            let mut hashes = state.last_hashes.lock().unwrap();
+            let index = state.index.lock().unwrap();
+            if let Err(e) = process_write_event(&canonical, &lookup, &mut hashes, &index) {
+                log::error!("Failed to synthesize initial backup for {canonical:?}: {e:?}");
+            }
+        }
+    });
+}
+
+/// Handle the removal of a watched file by disarming its watch and returning
+/// the rule to the pending set so recreation is caught.
+fn handle_removed_path(removed: &Path, state: &WatchState) {
+    let matched = {
+        let lookup = state.output_dir_lookup.lock().unwrap();
+        lookup.keys().find(|key| *key == removed).cloned()
+    };
+
+    if let Some(key) = matched {
+        let target = state.output_dir_lookup.lock().unwrap().remove(&key).unwrap();
+        if let Err(e) = state.watcher.lock().unwrap().unwatch(&key) {
+            log::debug!("Couldn't unwatch removed path {key:?}: {e:?}");
+        }
+        state.last_hashes.lock().unwrap().remove(&key);
+        state.pending.lock().unwrap().insert(key.clone(), target);
+        log::info!("Watched file {key:?} was removed; returned to pending for re-arm.");
+    }
+}
+
+/// Re-parse the configuration file and apply it to the running watcher,
+/// incrementally arming and disarming watches. A parse failure is logged and
+/// the previously-active configuration is left in place.
+fn reload_config(config_path: &Path, state: &WatchState) {
+    let config_file = match File::open(config_path) {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Couldn't reopen config {config_path:?} for reload: {e:?}");
+            return;
+        }
+    };
+
+    let parsed = match parse_config_file(&config_file, config_path) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::error!("Config reload failed, keeping previous configuration: {e:?}");
+            return;
+        }
+    };
+
+    log::info!("Configuration changed; applying updated rules.");
+
+    let mut lookup = state.output_dir_lookup.lock().unwrap();
+    let mut watcher = state.watcher.lock().unwrap();
+    let mut hashes = state.last_hashes.lock().unwrap();
+
+    let old_keys: Vec<PathBuf> = lookup.keys().cloned().collect();
+
+    // Disarm watches for files no longer covered by any rule.
+    for old in &old_keys {
+        if !parsed.output_dir_lookup.contains_key(old) {
+            if let Err(e) = watcher.unwatch(old) {
+                log::debug!("Couldn't unwatch dropped path {old:?}: {e:?}");
+            }
+            hashes.remove(old);
+            log::debug!("Stopped watching {old:?} after config reload.");
+        }
+    }
+
+    // Arm watches for newly-added files and seed their dedup hash.
+    for new in parsed.output_dir_lookup.keys() {
+        if !lookup.contains_key(new) {
+            if let Err(e) = watcher.watch(new, RecursiveMode::NonRecursive) {
+                log::error!("Failed to watch newly configured {new:?}: {e:?}");
+                continue;
+            }
+            match hash_file(new) {
+                Ok(hash) => {
+                    hashes.insert(new.clone(), hash);
+                }
+                Err(e) => log::error!("Failed to hash {new:?} while reloading config: {e:?}"),
+            }
+            log::debug!("Started watching {new:?} after config reload.");
+        }
+    }
+
+    *lookup = parsed.output_dir_lookup;
+    *state.pending.lock().unwrap() = parsed.pending;
+}
+
+/// Re-arm the watch on the config file after an atomic save replaced it
+/// (write-temp + rename drops the old inode the watch was following), then
+/// reload the new contents.
+fn rearm_config_watch(config_canonical: &Path, state: &WatchState) {
+    if let Err(e) = state
+        .watcher
+        .lock()
+        .unwrap()
+        .watch(config_canonical, RecursiveMode::NonRecursive)
+    {
+        log::error!("Failed to re-arm config watch on {config_canonical:?}: {e:?}");
+        return;
+    }
+    reload_config(config_canonical, state);
+}
+
 /// Simple file backup tool.
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct CliArgs {
-    /// The YAML configuration file.
-    config_file: String,
+    /// The YAML configuration file. When omitted, snapper searches the
+    /// standard config locations and creates a default one if none exist.
+    config_file: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Restore a file from the most recent recorded snapshot.
+    Restore {
+        /// The original file to restore.
+        file: String,
+
+        /// Restore the newest snapshot at or before this timestamp
+        /// (`%Y%m%d-%H%M%S-%6f`), instead of the most recent one.
+        #[clap(long)]
+        at: Option<String>,
+    },
 }
 
 fn main() {
@@ -118,12 +725,58 @@ fn main() {
 
     let args = CliArgs::parse();
 
-    let config_file = File::open(args.config_file)
+    let config_path = resolve_config_path(args.config_file);
+    let config_file = File::open(&config_path)
         .unwrap_or_else(|e| panic!("The specified configuration file can't be opened. {e:?}"));
 
-    let output_dir_lookup = parse_config_file(&config_file);
+    let ParsedConfig {
+        output_dir_lookup,
+        pending,
+        index_db_path,
+    } = parse_config_file(&config_file, &config_path).expect("Failed to parse config file.");
+
+    let index = open_index(&index_db_path).expect("Failed to open the snapshot index database.");
+
+    if let Some(Command::Restore { file, at }) = &args.command {
+        if let Err(e) = restore_snapshot(&index, &output_dir_lookup, file, at.as_deref()) {
+            log::error!("Failed to restore {file:?}: {e:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let (mut watcher, event_receiver) = start_file_watcher(&output_dir_lookup);
+
+    // Watch the config file itself so rule changes are picked up live.
+    let config_canonical = config_path.canonicalize().unwrap_or(config_path.clone());
+    if let Err(e) = watcher.watch(&config_canonical, RecursiveMode::NonRecursive) {
+        log::error!("Failed to watch config file {config_canonical:?} for changes: {e:?}");
+    }
+
+    // Seed the per-file hash cache from the current on-disk contents so the
+    // first spurious Write (e.g. a chmod-adjacent rewrite) doesn't produce a
+    // redundant snapshot.
+    let mut last_hashes: HashMap<PathBuf, u128> = HashMap::new();
+    for file_path in output_dir_lookup.keys() {
+        match hash_file(file_path) {
+            Ok(hash) => {
+                last_hashes.insert(file_path.clone(), hash);
+            }
+            Err(e) => {
+                log::error!("Failed to hash {file_path:?} while seeding dedup cache: {e:?}");
+            }
+        }
+    }
 
-    let (_watcher, event_receiver) = start_file_watcher(&output_dir_lookup);
+    let state = WatchState {
+        watcher: Arc::new(Mutex::new(watcher)),
+        output_dir_lookup: Arc::new(Mutex::new(output_dir_lookup)),
+        pending: Arc::new(Mutex::new(pending)),
+        last_hashes: Arc::new(Mutex::new(last_hashes)),
+        index: Arc::new(Mutex::new(index)),
+    };
+
+    spawn_pending_poller(state.clone());
 
     loop {
         match event_receiver.recv().unwrap() {
@@ -132,14 +785,28 @@ fn main() {
             }
             DebouncedEvent::NoticeRemove(path_buf) => {
                 log::debug!("NoticeRemove event: {path_buf:?} is being removed.");
+                handle_removed_path(&path_buf, &state);
             }
             DebouncedEvent::Create(path_buf) => {
                 log::debug!("Create event: {path_buf:?} was just created.");
+                if path_buf == config_canonical {
+                    rearm_config_watch(&config_canonical, &state);
+                }
             }
             DebouncedEvent::Write(path_buf) => {
                 log::debug!("Write event: {path_buf:?} was just written to.");
 
-                if let Err(e) = process_write_event(&path_buf, &output_dir_lookup) {
+                if path_buf == config_canonical {
+                    reload_config(&config_canonical, &state);
+                    continue;
+                }
+
+                let lookup = state.output_dir_lookup.lock().unwrap();
+                let mut hashes = state.last_hashes.lock().unwrap();
+                let index = state.index.lock().unwrap();
+                if let Err(e) =
+                    process_write_event(&path_buf, &lookup, &mut hashes, &index)
+                {
                     log::error!("Error while processing write event in file: {path_buf:?}: {e:?}");
                 }
             }
@@ -148,11 +815,15 @@ fn main() {
             }
             DebouncedEvent::Remove(path_buf) => {
                 log::debug!("Remove event: {path_buf:?} removed.");
+                handle_removed_path(&path_buf, &state);
             }
             DebouncedEvent::Rename(old_path_buf, new_path_buf) => {
                 log::debug!(
                     "Rename event: Old path_buf {old_path_buf:?} renamed to {new_path_buf:?}."
                 );
+                if new_path_buf == config_canonical {
+                    rearm_config_watch(&config_canonical, &state);
+                }
             }
             DebouncedEvent::Rescan => {
                 log::debug!("Rescan event.");
@@ -165,3 +836,171 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh SQLite index backed by an in-memory database.
+    fn test_index() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute(
+                "CREATE TABLE snapshots (
+                    id INTEGER PRIMARY KEY,
+                    source_path TEXT NOT NULL,
+                    backup_path TEXT NOT NULL,
+                    timestamp TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    content_hash TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        connection
+    }
+
+    /// A unique, empty scratch directory for a test.
+    fn scratch_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("snapper-test-{}-{tag}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write a snapshot file named the way `backup_by_copy` names them,
+    /// record a matching index row, and return its path.
+    fn write_snapshot(
+        dir: &Path,
+        index: &Connection,
+        timestamp: &str,
+        basename: &str,
+        size: usize,
+    ) -> PathBuf {
+        let path = dir.join(format!("{timestamp}-{basename}"));
+        std::fs::write(&path, vec![0u8; size]).unwrap();
+        index
+            .execute(
+                "INSERT INTO snapshots (source_path, backup_path, timestamp, size, content_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![basename, path.to_string_lossy(), timestamp, size as u64, "0"],
+            )
+            .unwrap();
+        path
+    }
+
+    fn remaining(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn row_count(index: &Connection) -> i64 {
+        index
+            .query_row("SELECT COUNT(*) FROM snapshots", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn keep_last_retains_newest_and_ignores_other_rules() {
+        let dir = scratch_dir("keep-last");
+        let index = test_index();
+        for ts in [
+            "20240101-120000-000000",
+            "20240101-120001-000000",
+            "20240101-120002-000000",
+            "20240101-120003-000000",
+        ] {
+            write_snapshot(&dir, &index, ts, "foo.txt", 10);
+        }
+        // A snapshot for a different source sharing the directory must survive.
+        write_snapshot(&dir, &index, "20240101-120000-000000", "bar.txt", 10);
+
+        let retention = Retention {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        prune_backups(&dir, OsStr::new("foo.txt"), &retention, &index).unwrap();
+
+        let files = remaining(&dir);
+        assert!(files.iter().any(|f| f.starts_with("20240101-120002")));
+        assert!(files.iter().any(|f| f.starts_with("20240101-120003")));
+        assert!(files.iter().any(|f| f.ends_with("-bar.txt")));
+        assert_eq!(files.len(), 3);
+        // Two foo.txt rows pruned; two foo.txt + one bar.txt rows remain.
+        assert_eq!(row_count(&index), 3);
+    }
+
+    #[test]
+    fn byte_budget_always_keeps_newest() {
+        let dir = scratch_dir("bytes");
+        let index = test_index();
+        for ts in [
+            "20240101-120000-000000",
+            "20240101-120001-000000",
+            "20240101-120002-000000",
+        ] {
+            write_snapshot(&dir, &index, ts, "foo.txt", 100);
+        }
+
+        // Budget smaller than a single snapshot must still keep the newest one.
+        let retention = Retention {
+            max_total_bytes: Some(50),
+            ..Default::default()
+        };
+        prune_backups(&dir, OsStr::new("foo.txt"), &retention, &index).unwrap();
+
+        let files = remaining(&dir);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].starts_with("20240101-120002"));
+        assert_eq!(row_count(&index), 1);
+    }
+
+    #[test]
+    fn max_age_prunes_only_old_snapshots() {
+        let dir = scratch_dir("age");
+        let index = test_index();
+        write_snapshot(&dir, &index, "20000101-000000-000000", "foo.txt", 10);
+        let recent = chrono::Utc::now().format("%Y%m%d-%H%M%S-%6f").to_string();
+        write_snapshot(&dir, &index, &recent, "foo.txt", 10);
+
+        let retention = Retention {
+            max_age_days: Some(1),
+            ..Default::default()
+        };
+        prune_backups(&dir, OsStr::new("foo.txt"), &retention, &index).unwrap();
+
+        let files = remaining(&dir);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with(&format!("{recent}-foo.txt")));
+    }
+
+    #[test]
+    fn select_snapshot_picks_newest_or_nearest_before() {
+        let index = test_index();
+        for (ts, backup) in [
+            ("20240101-120000-000000", "/b/one"),
+            ("20240101-120001-000000", "/b/two"),
+            ("20240101-120002-000000", "/b/three"),
+        ] {
+            index
+                .execute(
+                    "INSERT INTO snapshots (source_path, backup_path, timestamp, size, content_hash)
+                     VALUES (?1, ?2, ?3, 0, '0')",
+                    rusqlite::params!["/src/foo", backup, ts],
+                )
+                .unwrap();
+        }
+
+        assert_eq!(select_snapshot(&index, "/src/foo", None).unwrap(), "/b/three");
+        assert_eq!(
+            select_snapshot(&index, "/src/foo", Some("20240101-120001-000000")).unwrap(),
+            "/b/two"
+        );
+        assert!(select_snapshot(&index, "/src/foo", Some("20230101-000000-000000")).is_err());
+        assert!(select_snapshot(&index, "/src/missing", None).is_err());
+    }
+}